@@ -11,6 +11,7 @@ use {
     digest::Digest,
     failure::Error,
     num_integer::div_rem,
+    std::io::{self, Read},
     std::marker::PhantomData,
 };
 
@@ -18,24 +19,72 @@ use {
 enum GcsError {
     #[fail(display = "The limit for the number of elements has been reached")]
     LimitReached,
+    #[fail(display = "GCS header is truncated")]
+    TruncatedHeader,
+    #[fail(display = "GCS header has an unrecognised magic/version byte")]
+    InvalidHeader,
+    #[fail(display = "declared element count does not match the encoded payload")]
+    ElementCountMismatch,
+    #[fail(display = "cannot union two GCS built with different n, m, or range reduction")]
+    IncompatibleFilters,
+}
+
+/// Magic/version byte prefixed to a [`Gcs::serialize`](struct.Gcs.html#method.serialize) payload.
+const GCS_MAGIC: u8 = 0xFC;
+
+/// Strategy used to map a 64-bit hash into the range `[0, n*m)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeReduction {
+    /// `hash % (n*m)`. The default; matches the historical behaviour and
+    /// the Python `gcs` interop vectors, despite its modulo bias.
+    Modulo,
+    /// `((hash as u128) * (n*m) as u128) >> 64`. Unbiased, division-free
+    /// alternative to [`Modulo`](#variant.Modulo), BIP158-style.
+    MultiplyShift,
+}
+
+impl Default for RangeReduction {
+    fn default() -> Self {
+        RangeReduction::Modulo
+    }
 }
 
 /// Builder for a GCS
 #[derive(Clone, Debug)]
 pub struct GcsBuilder<D: Digest> {
     n: u64,
-    p: u8,
+    m: u64,
+    reduction: RangeReduction,
     values: Vec<u64>,
     digest: PhantomData<D>,
 }
 
 impl<D: Digest> GcsBuilder<D> {
     /// Creates a new GcsBuilder from n and p, where n is the number of items
-    /// to be stored in the set and 1/2^p is the probability of a false positive
+    /// to be stored in the set and 1/2^p is the probability of a false positive.
+    /// A convenience wrapper around [`with_modulus`](#method.with_modulus).
     pub fn new(n: u64, p: u8) -> Self {
+        Self::with_modulus(n, 1u64 << p)
+    }
+
+    /// Creates a new GcsBuilder from n and an arbitrary modulus m, where n is
+    /// the number of items to be stored in the set and 1/m is the probability
+    /// of a false positive. Unlike [`new`](#method.new), m need not be a
+    /// power of two. Maps hashes to `[0, n*m)` with [`RangeReduction::Modulo`];
+    /// use [`with_reduction`](#method.with_reduction) for the multiply-shift
+    /// mapping instead.
+    pub fn with_modulus(n: u64, m: u64) -> Self {
+        Self::with_reduction(n, m, RangeReduction::default())
+    }
+
+    /// Creates a new GcsBuilder from n, an arbitrary modulus m, and an
+    /// explicit [`RangeReduction`] strategy for mapping hashes into
+    /// `[0, n*m)`.
+    pub fn with_reduction(n: u64, m: u64, reduction: RangeReduction) -> Self {
         GcsBuilder {
             n,
-            p,
+            m,
+            reduction,
             values: Vec::new(),
             digest: PhantomData,
         }
@@ -44,7 +93,8 @@ impl<D: Digest> GcsBuilder<D> {
     /// Adds an entry to the set, and returns an error if more than N items are added
     pub fn insert(&mut self, input: &[u8]) -> Result<(), Error> {
         if (self.values.len() as u64) < self.n {
-            self.values.push(digest_value::<D>(self.n, self.p, input));
+            self.values
+                .push(digest_value::<D>(self.n, self.m, self.reduction, input));
             Ok(())
         } else {
             Err(GcsError::LimitReached.into())
@@ -53,7 +103,8 @@ impl<D: Digest> GcsBuilder<D> {
 
     /// Adds an entry to the set, does not error if more than N items are added
     pub fn insert_unchecked(&mut self, input: &[u8]) {
-        self.values.push(digest_value::<D>(self.n, self.p, input));
+        self.values
+            .push(digest_value::<D>(self.n, self.m, self.reduction, input));
     }
 
     /// Consumes the builder and creates the encoded set
@@ -69,45 +120,70 @@ impl<D: Digest> GcsBuilder<D> {
         // Apply golomb encoding
         let mut bits = BitVec::<bitvec::BigEndian>::new();
         for val in self.values {
-            bits.append(&mut golomb_encode(val, self.p))
+            bits.append(&mut golomb_encode(val, self.m))
         }
         out.append(&mut bits);
 
-        Gcs::<D>::new(self.n, self.p, out)
+        Gcs::<D>::with_reduction(self.n, self.m, self.reduction, out)
     }
 }
 
 /// A Golomb-coded Set
 pub struct Gcs<D: Digest> {
     n: u64,
-    p: u8,
+    m: u64,
+    reduction: RangeReduction,
     bits: BitVec,
     digest: PhantomData<D>,
 }
 
 impl<D: Digest> Gcs<D> {
-    /// Create a GCS from n, p and a BitVec of the Golomb-Rice encoded values,
-    /// where n is the number of items the GCS was defined with and 1/2^p is
-    /// the probability of a false positive
-    pub fn new(n: u64, p: u8, bits: BitVec) -> Self {
+    /// Create a GCS from n, m and a BitVec of the Golomb encoded values,
+    /// where n is the number of items the GCS was defined with and 1/m is
+    /// the probability of a false positive. Maps hashes with
+    /// [`RangeReduction::Modulo`]; see [`with_reduction`](#method.with_reduction).
+    pub fn new(n: u64, m: u64, bits: BitVec) -> Self {
+        Self::with_reduction(n, m, RangeReduction::default(), bits)
+    }
+
+    /// Create a GCS from n, m, an explicit [`RangeReduction`] strategy, and a
+    /// BitVec of the Golomb encoded values. The reduction strategy must
+    /// match the one the set was built or hashed with, or queries will
+    /// compare against the wrong hash range.
+    pub fn with_reduction(n: u64, m: u64, reduction: RangeReduction, bits: BitVec) -> Self {
         Gcs {
             n,
-            p,
+            m,
+            reduction,
             bits,
             digest: PhantomData,
         }
     }
 
+    /// Reads the raw Golomb-encoded payload from `reader` and builds a GCS
+    /// from it, given the same n, m, and exact bit length (`as_bits().len()`)
+    /// the set was originally built with. Maps hashes with
+    /// [`RangeReduction::Modulo`]; use [`with_reduction`](#method.with_reduction)
+    /// for a set built with the multiply-shift mapping.
+    pub fn decode_from<R: Read>(mut reader: R, n: u64, m: u64, bit_len: u64) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+
+        if bit_len > buf.len() as u64 * 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "bit_len exceeds the bytes read from reader",
+            ));
+        }
+
+        Ok(Gcs::new(n, m, bits_from_bytes(&buf, bit_len)))
+    }
+
     /// Returns whether or not an input is contained in the set. If false the
     /// input is definitely not present, if true the input is probably present
     pub fn contains(&self, input: &[u8]) -> bool {
-        let mut values = golomb_decode(self.bits.clone().iter().peekable(), self.p);
-
-        for i in 1..values.len() {
-            values[i] += values[i - 1];
-        }
-
-        values.contains(&digest_value::<D>(self.n, self.p, input))
+        let target = digest_value::<D>(self.n, self.m, self.reduction, input);
+        self.iter_values().any(|value| value == target)
     }
 
     /// Get the raw data bytes from a GCS
@@ -117,20 +193,331 @@ impl<D: Digest> Gcs<D> {
 
     /// Get the raw values encoded in the BitVec
     pub fn values(&self) -> Vec<u64> {
-        golomb_decode(self.bits.clone().iter().peekable(), self.p)
+        self.iter_values().collect()
+    }
+
+    /// Lazily decodes the Golomb stream, yielding the cumulative set values
+    /// one at a time, borrowing `self.bits` rather than cloning it.
+    pub fn iter_values(&self) -> impl Iterator<Item = u64> + '_ {
+        let mut iter = self.bits.iter().peekable();
+        let mut acc = 0u64;
+        let m = self.m;
+
+        std::iter::from_fn(move || {
+            if iter.peek().is_none() {
+                return None;
+            }
+            acc += next_delta(&mut iter, m);
+            Some(acc)
+        })
+    }
+
+    /// Returns whether or not any of `inputs` is contained in the set. If
+    /// false, none of the inputs are present; if true, at least one is
+    /// probably present. Checks all `k` inputs in a single `O(n + k log k)`
+    /// pass over [`iter_values`](#method.iter_values).
+    pub fn match_any(&self, inputs: &[&[u8]]) -> bool {
+        let mut targets = self.target_values(inputs);
+        if targets.is_empty() {
+            return false;
+        }
+        targets.sort_unstable();
+        targets.dedup();
+
+        let mut ti = 0usize;
+        for acc in self.iter_values() {
+            while ti < targets.len() && targets[ti] <= acc {
+                if targets[ti] == acc {
+                    return true;
+                }
+                ti += 1;
+            }
+            if ti >= targets.len() {
+                break;
+            }
+        }
+
+        false
+    }
+
+    /// Returns whether or not all of `inputs` are contained in the set. If
+    /// false, at least one input is definitely not present; if true, all are
+    /// probably present. See [`match_any`](#method.match_any) for the
+    /// single-pass strategy.
+    pub fn match_all(&self, inputs: &[&[u8]]) -> bool {
+        let mut targets = self.target_values(inputs);
+        if targets.is_empty() {
+            return true;
+        }
+        targets.sort_unstable();
+        targets.dedup();
+
+        let mut ti = 0usize;
+        let mut hits = 0usize;
+
+        for acc in self.iter_values() {
+            while ti < targets.len() && targets[ti] <= acc {
+                if targets[ti] == acc {
+                    hits += 1;
+                }
+                ti += 1;
+            }
+            if ti >= targets.len() {
+                break;
+            }
+        }
+
+        hits == targets.len()
+    }
+
+    fn target_values(&self, inputs: &[&[u8]]) -> Vec<u64> {
+        inputs
+            .iter()
+            .map(|input| digest_value::<D>(self.n, self.m, self.reduction, input))
+            .collect()
+    }
+
+    /// Serializes the GCS to a self-describing, portable byte representation:
+    /// a magic/version byte, a flags byte encoding the [`RangeReduction`]
+    /// strategy, `n`, `m`, the number of encoded elements, and the exact bit
+    /// length as CompactSize integers, followed by the byte-aligned Golomb
+    /// payload. Round-trips through [`deserialize`](#method.deserialize).
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.push(GCS_MAGIC);
+        out.push(reduction_flags(self.reduction));
+        write_compact_size(&mut out, self.n);
+        write_compact_size(&mut out, self.m);
+        write_compact_size(&mut out, self.iter_values().count() as u64);
+        write_compact_size(&mut out, self.bits.len() as u64);
+        out.extend_from_slice((&self.bits[..]).as_ref());
+
+        out
+    }
+
+    /// Parses a GCS previously written by [`serialize`](#method.serialize),
+    /// validating the header, truncating the byte-aligned payload back down
+    /// to its declared exact bit length, and checking that the declared
+    /// element count matches the number of values actually encoded.
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, Error> {
+        let mut pos = 0usize;
+
+        let magic = *bytes.get(pos).ok_or(GcsError::TruncatedHeader)?;
+        pos += 1;
+        if magic != GCS_MAGIC {
+            return Err(GcsError::InvalidHeader.into());
+        }
+
+        let flags = *bytes.get(pos).ok_or(GcsError::TruncatedHeader)?;
+        pos += 1;
+        let reduction = reduction_from_flags(flags)?;
+
+        let n = read_compact_size(bytes, &mut pos)?;
+        let m = read_compact_size(bytes, &mut pos)?;
+        let count = read_compact_size(bytes, &mut pos)?;
+        let bit_len = read_compact_size(bytes, &mut pos)?;
+
+        if bit_len > (bytes.len() - pos) as u64 * 8 {
+            return Err(GcsError::TruncatedHeader.into());
+        }
+
+        let bits = bits_from_bytes(&bytes[pos..], bit_len);
+        let gcs = Gcs::<D>::with_reduction(n, m, reduction, bits);
+
+        if gcs.iter_values().count() as u64 != count {
+            return Err(GcsError::ElementCountMismatch.into());
+        }
+
+        Ok(gcs)
+    }
+
+    /// Merges `self` and `other` into a filter containing both sets'
+    /// members, without rehashing the original inputs (which neither filter
+    /// retains). Both filters must share the same `n`, `m`, and
+    /// [`RangeReduction`] strategy, since `n` is folded into the hash range.
+    /// A linear merge of the two decoded streams, dropping duplicates,
+    /// re-encoded as deltas.
+    pub fn union(&self, other: &Gcs<D>) -> Result<Gcs<D>, Error> {
+        if self.n != other.n || self.m != other.m || self.reduction != other.reduction {
+            return Err(GcsError::IncompatibleFilters.into());
+        }
+
+        let mut a = self.iter_values().peekable();
+        let mut b = other.iter_values().peekable();
+        let mut merged = Vec::new();
+
+        loop {
+            match (a.peek(), b.peek()) {
+                (Some(&x), Some(&y)) if x < y => {
+                    merged.push(x);
+                    a.next();
+                }
+                (Some(&x), Some(&y)) if x > y => {
+                    merged.push(y);
+                    b.next();
+                }
+                (Some(&x), Some(_)) => {
+                    merged.push(x);
+                    a.next();
+                    b.next();
+                }
+                (Some(&x), None) => {
+                    merged.push(x);
+                    a.next();
+                }
+                (None, Some(&y)) => {
+                    merged.push(y);
+                    b.next();
+                }
+                (None, None) => break,
+            }
+        }
+
+        let mut bits = BitVec::new();
+        let mut prev = 0u64;
+        for val in merged {
+            bits.append(&mut golomb_encode(val - prev, self.m));
+            prev = val;
+        }
+
+        Ok(Gcs::with_reduction(self.n, self.m, self.reduction, bits))
+    }
+}
+
+/// Encodes a [`RangeReduction`] strategy as a header flags byte.
+fn reduction_flags(reduction: RangeReduction) -> u8 {
+    match reduction {
+        RangeReduction::Modulo => 0,
+        RangeReduction::MultiplyShift => 1,
+    }
+}
+
+/// Decodes a [`RangeReduction`] strategy from a header flags byte.
+fn reduction_from_flags(flags: u8) -> Result<RangeReduction, Error> {
+    match flags {
+        0 => Ok(RangeReduction::Modulo),
+        1 => Ok(RangeReduction::MultiplyShift),
+        _ => Err(GcsError::InvalidHeader.into()),
+    }
+}
+
+/// Decodes the next delta (quotient * m + remainder) from a Golomb stream.
+///
+/// # Panics
+///
+/// Panics if the iterator is exhausted before a full value has been decoded.
+fn next_delta<I: Iterator<Item = bool>>(iter: &mut I, m: u64) -> u64 {
+    let mut quo = 0u64;
+    while iter.next().unwrap() {
+        quo += 1;
+    }
+    let rem = decode_remainder(iter, m);
+
+    quo * m + rem
+}
+
+/// Builds a `BitVec` of exactly `bit_len` bits from `bytes`, discarding the
+/// padding bits a byte-aligned buffer carries past the true bit length.
+fn bits_from_bytes(bytes: &[u8], bit_len: u64) -> BitVec {
+    let full: BitVec = bytes.to_vec().into();
+    let mut out = BitVec::new();
+    for bit in full.iter().take(bit_len as usize) {
+        out.push(bit);
+    }
+
+    out
+}
+
+/// Number of bits `b` needed for truncated binary coding of a value in `[0, m)`,
+/// i.e. `b = ceil(log2(m))`.
+fn truncated_binary_width(m: u64) -> u8 {
+    if m <= 1 {
+        0
+    } else {
+        (64 - (m - 1).leading_zeros()) as u8
+    }
+}
+
+/// Pushes the low `bits` bits of `value` onto `out`, most significant bit first.
+fn push_bits_msb(out: &mut BitVec, value: u64, bits: u8) {
+    for i in (0..bits).rev() {
+        out.push(value.get::<bitvec::LittleEndian>(i.into()));
+    }
+}
+
+/// Reads `bits` bits from `iter`, most significant bit first, into a `u64`.
+fn read_bits_msb<I: Iterator<Item = bool>>(iter: &mut I, bits: u8) -> u64 {
+    let mut val = 0u64;
+    for _ in 0..bits {
+        if iter.next().unwrap() {
+            val += 1;
+        }
+        val <<= 1;
+    }
+    val >>= 1;
+    val
+}
+
+/// `2^b - m`, without overflowing the `1u64 << b` shift when `b == 64`.
+fn pow2_sub(b: u8, m: u64) -> u64 {
+    if b == 64 {
+        m.wrapping_neg()
+    } else {
+        (1u64 << b) - m
+    }
+}
+
+/// Encodes a remainder `r` in `[0, m)` using truncated binary coding: let
+/// `b = ceil(log2(m))`; if `r < 2^b - m` emit `r` in `b - 1` bits, otherwise
+/// emit `r + (2^b - m)` in `b` bits. This degenerates to plain `b`-bit binary
+/// coding when `m` is a power of two.
+fn encode_remainder(rem: u64, m: u64) -> BitVec {
+    let b = truncated_binary_width(m);
+    let mut out = BitVec::new();
+
+    if b == 0 {
+        return out;
+    }
+
+    let threshold = pow2_sub(b, m);
+    if rem < threshold {
+        push_bits_msb(&mut out, rem, b - 1);
+    } else {
+        push_bits_msb(&mut out, rem + threshold, b);
     }
+
+    out
 }
 
-/// Perform Golomb-Rice encoding of n, with modulus 2^p
+/// Decodes a remainder encoded by [`encode_remainder`].
+fn decode_remainder<I: Iterator<Item = bool>>(iter: &mut I, m: u64) -> u64 {
+    let b = truncated_binary_width(m);
+
+    if b == 0 {
+        return 0;
+    }
+
+    let threshold = pow2_sub(b, m);
+    let x = read_bits_msb(iter, b - 1);
+
+    if x >= threshold {
+        let extra = read_bits_msb(iter, 1);
+        (x << 1) + extra - threshold
+    } else {
+        x
+    }
+}
+
+/// Perform Golomb encoding of n, with modulus m
 ///
 /// # Panics
 ///
-/// Panics if `p == 0`.
-fn golomb_encode(n: u64, p: u8) -> BitVec {
-    if p == 0 {
-        panic!("p cannot be 0");
+/// Panics if `m == 0`.
+fn golomb_encode(n: u64, m: u64) -> BitVec {
+    if m == 0 {
+        panic!("m cannot be 0");
     }
-    let (quo, rem) = div_rem(n, 2u64.pow(u32::from(p)));
+    let (quo, rem) = div_rem(n, m);
 
     let mut out = BitVec::new();
 
@@ -140,49 +527,44 @@ fn golomb_encode(n: u64, p: u8) -> BitVec {
     }
     out.push(false);
 
-    // Binary encoding of remainder in p bits
-    // remove vec and change to big end?
-    for i in (0..p).rev() {
-        out.push(rem.get::<bitvec::LittleEndian>(i.into()));
-    }
+    // Truncated binary encoding of the remainder
+    out.append(&mut encode_remainder(rem, m));
 
     out
 }
 
-/// Perform Golomb-Rice decoding of n, with modulus 2^p
-fn golomb_decode<I>(iter: I, p: u8) -> Vec<u64>
+/// Perform Golomb decoding of n, with modulus m
+fn golomb_decode<I>(iter: I, m: u64) -> Vec<u64>
 where
     I: Iterator<Item = bool>,
 {
     let mut out = Vec::<u64>::new();
     let mut iter = iter.peekable();
 
-    while let Some(_) = iter.peek() {
-        // parse unary encoded quotient
-        let mut quo = 0u64;
-        while iter.next().unwrap() {
-            quo += 1;
-        }
-
-        // parse binary encoded remainder
-        let mut rem = 0u64;
-        for _ in 0..p {
-            if iter.next().unwrap() {
-                rem += 1;
-            }
-            rem <<= 1;
-        }
-        rem >>= 1;
-
-        // push quo * p + rem
-        out.push(quo * 2u64.pow(u32::from(p)) + rem);
+    while iter.peek().is_some() {
+        out.push(next_delta(&mut iter, m));
     }
 
     out
 }
 
-fn digest_value<D: Digest>(n: u64, p: u8, input: &[u8]) -> u64 {
-    let val = if D::output_size() < 8 {
+fn digest_value<D: Digest>(n: u64, m: u64, reduction: RangeReduction, input: &[u8]) -> u64 {
+    match reduction {
+        // Kept bit-for-bit for the Python `gcs` interop vectors.
+        RangeReduction::Modulo => narrow_digest_u64::<D>(input) % (n * m),
+        // Needs the full 64 bits of entropy, unlike narrow_digest_u64.
+        RangeReduction::MultiplyShift => {
+            let val = wide_digest_u64::<D>(input);
+            ((u128::from(val) * u128::from(n * m)) >> 64) as u64
+        }
+    }
+}
+
+/// Reads a digest into a `u64`, zero-padding the high bytes when shorter
+/// than 8 bytes. Kept only for [`RangeReduction::Modulo`] compatibility
+/// with the Python `gcs` interop vectors.
+fn narrow_digest_u64<D: Digest>(input: &[u8]) -> u64 {
+    if D::output_size() < 8 {
         let mut buf = [0u8; 8];
         let digest = D::digest(input);
         for i in 0..D::output_size() {
@@ -192,21 +574,213 @@ fn digest_value<D: Digest>(n: u64, p: u8, input: &[u8]) -> u64 {
         byteorder::BigEndian::read_u64(&buf)
     } else {
         byteorder::BigEndian::read_u64(&D::digest(input)[..8])
+    }
+}
+
+/// Reads a digest into a `u64` spanning the full 64 bits regardless of
+/// digest length, by tiling short digests instead of zero-padding.
+fn wide_digest_u64<D: Digest>(input: &[u8]) -> u64 {
+    let digest = D::digest(input);
+
+    if digest.len() >= 8 {
+        byteorder::BigEndian::read_u64(&digest[..8])
+    } else {
+        let mut buf = [0u8; 8];
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = digest[i % digest.len()];
+        }
+
+        byteorder::BigEndian::read_u64(&buf)
+    }
+}
+
+/// Writes `value` as a Bitcoin-style CompactSize integer: a single byte for
+/// values below `0xfd`, otherwise a marker byte (`0xfd`/`0xfe`/`0xff`)
+/// followed by the value as a little-endian `u16`/`u32`/`u64`.
+fn write_compact_size(out: &mut Vec<u8>, value: u64) {
+    if value < 0xfd {
+        out.push(value as u8);
+    } else if value <= u64::from(u16::max_value()) {
+        out.push(0xfd);
+        let mut buf = [0u8; 2];
+        byteorder::LittleEndian::write_u16(&mut buf, value as u16);
+        out.extend_from_slice(&buf);
+    } else if value <= u64::from(u32::max_value()) {
+        out.push(0xfe);
+        let mut buf = [0u8; 4];
+        byteorder::LittleEndian::write_u32(&mut buf, value as u32);
+        out.extend_from_slice(&buf);
+    } else {
+        out.push(0xff);
+        let mut buf = [0u8; 8];
+        byteorder::LittleEndian::write_u64(&mut buf, value);
+        out.extend_from_slice(&buf);
+    }
+}
+
+/// Reads a CompactSize integer written by [`write_compact_size`] starting at
+/// `*pos`, advancing `*pos` past it.
+fn read_compact_size(bytes: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let prefix = *bytes.get(*pos).ok_or(GcsError::TruncatedHeader)?;
+    *pos += 1;
+
+    let value = match prefix {
+        0xfd => {
+            let slice = bytes
+                .get(*pos..*pos + 2)
+                .ok_or(GcsError::TruncatedHeader)?;
+            *pos += 2;
+            u64::from(byteorder::LittleEndian::read_u16(slice))
+        }
+        0xfe => {
+            let slice = bytes
+                .get(*pos..*pos + 4)
+                .ok_or(GcsError::TruncatedHeader)?;
+            *pos += 4;
+            u64::from(byteorder::LittleEndian::read_u32(slice))
+        }
+        0xff => {
+            let slice = bytes
+                .get(*pos..*pos + 8)
+                .ok_or(GcsError::TruncatedHeader)?;
+            *pos += 8;
+            byteorder::LittleEndian::read_u64(slice)
+        }
+        _ => u64::from(prefix),
     };
 
-    val % (n * 2u64.pow(u32::from(p)))
+    Ok(value)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use digest::generic_array::{typenum::U8, GenericArray};
     use proptest::prelude::*;
+    use std::{collections::hash_map::DefaultHasher, hash::Hasher};
 
     proptest! {
         // Ranges need to be extended after improving performance
         #[test]
-        fn golomb_single(n in 0u64..100000u64, p in 2u8..16) {
-            assert_eq!(n, golomb_decode(golomb_encode(n, p).iter().peekable(), p)[0]);
+        fn golomb_single(n in 0u64..100000u64, m in 2u64..10000u64) {
+            assert_eq!(n, golomb_decode(golomb_encode(n, m).iter().peekable(), m)[0]);
+        }
+    }
+
+    /// Non-cryptographic `Digest` used to exercise the `Gcs`/`GcsBuilder`
+    /// surface in tests without pulling in a real hash crate.
+    #[derive(Default)]
+    struct TestDigest(DefaultHasher);
+
+    impl Digest for TestDigest {
+        type OutputSize = U8;
+
+        fn new() -> Self {
+            TestDigest(DefaultHasher::new())
+        }
+
+        fn input<B: AsRef<[u8]>>(&mut self, data: B) {
+            self.0.write(data.as_ref());
+        }
+
+        fn chain<B: AsRef<[u8]>>(mut self, data: B) -> Self {
+            self.input(data);
+            self
+        }
+
+        fn result(self) -> GenericArray<u8, Self::OutputSize> {
+            GenericArray::clone_from_slice(&self.0.finish().to_be_bytes())
+        }
+
+        fn result_reset(&mut self) -> GenericArray<u8, Self::OutputSize> {
+            let out = GenericArray::clone_from_slice(&self.0.finish().to_be_bytes());
+            self.0 = DefaultHasher::new();
+            out
+        }
+
+        fn reset(&mut self) {
+            self.0 = DefaultHasher::new();
+        }
+
+        fn output_size() -> usize {
+            8
+        }
+
+        fn digest(data: &[u8]) -> GenericArray<u8, Self::OutputSize> {
+            let mut hasher = DefaultHasher::new();
+            hasher.write(data);
+            GenericArray::clone_from_slice(&hasher.finish().to_be_bytes())
         }
     }
+
+    #[test]
+    fn match_any_and_match_all_agree_with_contains() {
+        let mut builder = GcsBuilder::<TestDigest>::new(20, 10);
+        let members: &[&[u8]] = &[b"alpha", b"beta", b"gamma"];
+        for member in members {
+            builder.insert_unchecked(member);
+        }
+        let gcs = builder.build();
+
+        let queries: &[&[u8]] = &[b"alpha", b"beta", b"gamma", b"not-there"];
+        let any = queries.iter().any(|q| gcs.contains(q));
+        let all = queries.iter().all(|q| gcs.contains(q));
+
+        assert_eq!(gcs.match_any(queries), any);
+        assert_eq!(gcs.match_all(queries), all);
+    }
+
+    #[test]
+    fn serialize_round_trips_through_deserialize() {
+        let mut builder = GcsBuilder::<TestDigest>::new(50, 10);
+        for i in 0..50u32 {
+            builder.insert_unchecked(&i.to_be_bytes());
+        }
+        let gcs = builder.build();
+
+        let bytes = gcs.serialize();
+        let decoded = Gcs::<TestDigest>::deserialize(&bytes).unwrap();
+
+        assert_eq!(gcs.values(), decoded.values());
+    }
+
+    #[test]
+    fn multiply_shift_round_trips_through_contains() {
+        let mut builder =
+            GcsBuilder::<TestDigest>::with_reduction(30, 1024, RangeReduction::MultiplyShift);
+        let members: Vec<[u8; 4]> = (0..30u32).map(|i| i.to_be_bytes()).collect();
+        for member in &members {
+            builder.insert_unchecked(member);
+        }
+        let gcs = builder.build();
+
+        for member in &members {
+            assert!(gcs.contains(member));
+        }
+    }
+
+    #[test]
+    fn union_contains_both_sets_and_rejects_mismatched_params() {
+        let mut a_builder = GcsBuilder::<TestDigest>::new(10, 10);
+        let a_members: Vec<[u8; 4]> = (0..10u32).map(|i| i.to_be_bytes()).collect();
+        for member in &a_members {
+            a_builder.insert_unchecked(member);
+        }
+        let a = a_builder.build();
+
+        let mut b_builder = GcsBuilder::<TestDigest>::new(10, 10);
+        let b_members: Vec<[u8; 4]> = (100..110u32).map(|i| i.to_be_bytes()).collect();
+        for member in &b_members {
+            b_builder.insert_unchecked(member);
+        }
+        let b = b_builder.build();
+
+        let merged = a.union(&b).unwrap();
+        for member in a_members.iter().chain(b_members.iter()) {
+            assert!(merged.contains(member));
+        }
+
+        let mismatched = GcsBuilder::<TestDigest>::new(10, 20).build();
+        assert!(a.union(&mismatched).is_err());
+    }
 }